@@ -3,6 +3,7 @@
 // Use of this source code is governed by an MIT-style license that can be found
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
+use std::collections::HashMap;
 use std::path::Path;
 use std::io::{Read, Write, Result};
 
@@ -16,6 +17,13 @@ const INCLUDES: &'static [&'static str] = &[
     "grr/grr/proto",
 ];
 
+/// Environment variable pointing at a manifest of extra proto files to feed
+/// into the build, one colon-separated `path`, `path:include` or
+/// `path:include:package` entry per line (relative to the crate root), see
+/// [`extra_protos`] for the exact format. Lets downstream users register their
+/// own GRR-derived protos without patching this build script.
+const EXTRA_PROTOS_VAR: &'static str = "RRG_EXTRA_PROTOS_MANIFEST";
+
 fn main() {
     // Because GRR proto files are not something that PROST! can accept (because
     // of the missing package definitions), we create a temporary directory with
@@ -30,7 +38,7 @@ fn main() {
     for path_before in PROTOS {
         let path_after = tempdir.path().join(path_before);
 
-        patch_path(&path_before, &path_after)
+        patch_path(&path_before, &path_after, default_package(path_before))
             .expect(&format!("failed to patch file '{}'", path_before));
 
         protos.push(path_after);
@@ -41,33 +49,113 @@ fn main() {
         includes.push(path_after);
     }
 
-    prost_build::compile_protos(&protos, &includes)
+    for (path_before, include_before, package_before) in extra_protos() {
+        let path_after = tempdir.path().join(&path_before);
+        let package = package_before.as_deref()
+            .or_else(|| default_package(&path_before));
+
+        patch_path(&path_before, &path_after, package)
+            .expect(&format!("failed to patch file '{}'", path_before));
+
+        protos.push(path_after);
+
+        if let Some(include_before) = include_before {
+            includes.push(tempdir.path().join(include_before));
+        }
+    }
+
+    let mut config = prost_build::Config::new();
+
+    // Allow downstream users to derive `serde::{Serialize, Deserialize}` (for
+    // JSON export of responses) or other traits on the generated types, to
+    // pick how proto `bytes` fields are represented, and to switch selected
+    // map fields to a deterministically-ordered `BTreeMap`, without having to
+    // fork this build script. All of these are opt-in and leave the default
+    // generated output unchanged.
+    if let Ok(attribute) = std::env::var("RRG_PROTO_TYPE_ATTRIBUTE") {
+        config.type_attribute(".", attribute);
+    }
+    if let Ok(attribute) = std::env::var("RRG_PROTO_FIELD_ATTRIBUTE") {
+        config.field_attribute(".", attribute);
+    }
+    if let Ok(bytes_paths) = std::env::var("RRG_PROTO_BYTES") {
+        let paths = bytes_paths.split(',').collect::<Vec<_>>();
+        config.bytes(&paths);
+    }
+    if let Ok(btree_map_paths) = std::env::var("RRG_PROTO_BTREE_MAP") {
+        let paths = btree_map_paths.split(',').collect::<Vec<_>>();
+        config.btree_map(&paths);
+    }
+
+    config.compile_protos(&protos, &includes)
         .expect("failed to compile proto files");
 
-    // There is also a problem with one enum generated by PROST!: it's values
-    // use name mangling, but it's default value does not. This is likely a bug
-    // in PROST! itself, but for now we hack around it by replacing the spurious
-    // line in the output file ourselves.
+    // There is also a problem with some enums generated by PROST!: their
+    // values use name mangling, but their default value does not. This is
+    // likely a bug in PROST! itself, so we scan the generated file for every
+    // affected `Default` impl and fix it up ourselves.
     let outdir = std::env::var("OUT_DIR")
         .expect("no output directory");
 
     let target = Path::new(&outdir).join("grr.rs");
 
     let grr = std::fs::read_to_string(&target)
-        .expect("invalid generated Rust code")
-        .replace("TskFsAttrTypeDefault", "Default");
+        .expect("invalid generated Rust code");
+
+    let grr = fixup_enum_defaults(&grr);
 
     std::fs::write(&target, grr)
         .expect("failed to write updated output file");
 }
 
+/// Reads the extra proto manifest pointed at by [`EXTRA_PROTOS_VAR`], if any.
+///
+/// Each non-empty, non-comment (`#`) line is a proto path, optionally
+/// followed by `:include_dir` and `:package`, letting downstream users append
+/// their own proto files (and, optionally, an additional include directory
+/// and package name for each) to the hardcoded `PROTOS`/`INCLUDES` arrays
+/// above.
+fn extra_protos() -> Vec<(String, Option<String>, Option<String>)> {
+    let manifest = match std::env::var(EXTRA_PROTOS_VAR) {
+        Ok(manifest) => manifest,
+        Err(_) => return Vec::new(),
+    };
+
+    let contents = std::fs::read_to_string(&manifest)
+        .expect(&format!("failed to read extra proto manifest '{}'", manifest));
+
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.splitn(3, ':');
+
+            let path = parts.next().unwrap_or(line).to_string();
+            let include = parts.next().filter(|part| !part.is_empty()).map(str::to_string);
+            let package = parts.next().filter(|part| !part.is_empty()).map(str::to_string);
+
+            (path, include, package)
+        })
+        .collect()
+}
+
+/// Package names to inject into patched proto files, keyed by the path they
+/// are known under (as they appear in `PROTOS` or the [`EXTRA_PROTOS_VAR`]
+/// manifest). Unlisted paths are assumed to either already declare their own
+/// package or to have one supplied explicitly through the manifest.
+fn default_package(path: &str) -> Option<&'static str> {
+    PROTOS.iter().find(|&&proto| proto == path).map(|_| "grr")
+}
+
 /// Patches given file at path `input`, writing patched content at `output`.
 ///
-/// This function takes a path to malformed (i.e. lacking package definition)
-/// GRR proto file and converts it to something that PROST! can understand. This
-/// workaround has to be used as long as GRR does not fixes its proto files
-/// upstream, which might be hard because of compatibility reasons.
-fn patch_path<PI, PO>(input: PI, output: PO) -> Result<()>
+/// This function takes a path to a malformed (i.e. lacking a package
+/// definition) GRR proto file and converts it to something that PROST! can
+/// understand. This workaround has to be used as long as GRR does not fix its
+/// proto files upstream, which might be hard because of compatibility
+/// reasons. `package` is the package name to inject; pass `None` when the
+/// file already declares its own or should be left without one.
+fn patch_path<PI, PO>(input: PI, output: PO, package: Option<&str>) -> Result<()>
 where
     PI: AsRef<Path>,
     PO: AsRef<Path>,
@@ -75,16 +163,18 @@ where
     let mut input = file::open(&input)?;
     let mut output = file::create(&output)?;
 
-    patch_buffer(&mut input, &mut output)
+    patch_buffer(&mut input, &mut output, package)
 }
 
 /// Patches given `input` buffer, writing patched content to `output`.
 ///
-/// This function takes a buffer with malformed (lacking package definition)
-/// GRR proto file and converts it to something that PROST! can understand. This
-/// workaround has to be used as long as GRR does not fixes its proto files
-/// upstream, which might be hard because of compatibility reasons.
-fn patch_buffer<R, W>(input: &mut R, output: &mut W) -> Result<()>
+/// This function takes a buffer with a malformed (lacking a package
+/// definition) GRR proto file and converts it to something that PROST! can
+/// understand. This workaround has to be used as long as GRR does not fix its
+/// proto files upstream, which might be hard because of compatibility
+/// reasons. If `input` already declares a `package`, it is left untouched
+/// even when `package` is given, so that files are never double-packaged.
+fn patch_buffer<R, W>(input: &mut R, output: &mut W, package: Option<&str>) -> Result<()>
 where
     R: Read,
     W: Write,
@@ -92,16 +182,179 @@ where
     let mut buffer = String::new();
     input.read_to_string(&mut buffer)?;
 
+    let has_package = buffer.lines()
+        .any(|line| line.trim_start().starts_with("package "));
+
     for line in buffer.lines() {
         writeln!(output, "{}", line)?;
-        if line.starts_with("syntax =") {
-            writeln!(output, "package grr;")?;
+        if !has_package && line.starts_with("syntax =") {
+            if let Some(package) = package {
+                writeln!(output, "package {};", package)?;
+            }
         }
     }
 
     Ok(())
 }
 
+/// Fixes up every PROST!-generated `impl ... Default for <Enum>` whose
+/// returned variant identifier does not match any of `<Enum>`'s declared
+/// variants.
+///
+/// PROST! occasionally mangles an enum's default-value identifier into
+/// `<Enum><Variant>` (e.g. `TskFsAttrTypeDefault` for variant `Default` of
+/// enum `TskFsAttrType`) instead of using the plain variant name. This scans
+/// `source` for every such impl and, when the referenced variant is unknown,
+/// strips the enum name prefix to recover the real one.
+///
+/// This is a small hand-rolled scanner rather than a regular expression, so
+/// that it does not depend on whether PROST! happens to emit the impl (and
+/// its `-> Self { ... }` body) on one line or several.
+fn fixup_enum_defaults(source: &str) -> String {
+    let variants_by_enum = enum_variants(source);
+
+    let mut output = String::with_capacity(source.len());
+    let mut cursor = source;
+
+    while let Some(marker) = cursor.find("Default for ") {
+        let (before, after) = cursor.split_at(marker);
+        output.push_str(before);
+        output.push_str("Default for ");
+
+        let after = &after["Default for ".len()..];
+        let enum_name = take_ident(after);
+        output.push_str(enum_name);
+
+        let after_name = &after[enum_name.len()..];
+        let (prefix, body) = match take_braced_block(after_name) {
+            Some(found) => found,
+            None => {
+                output.push_str(after_name);
+                cursor = "";
+                break;
+            }
+        };
+        output.push_str(prefix);
+        output.push_str(&fixup_variant_refs(body, enum_name, &variants_by_enum));
+
+        cursor = &after_name[prefix.len() + body.len()..];
+    }
+
+    output.push_str(cursor);
+    output
+}
+
+/// Collects the declared variant names of every `pub enum` in `source`,
+/// keyed by enum name.
+fn enum_variants(source: &str) -> HashMap<String, Vec<String>> {
+    let mut variants_by_enum = HashMap::new();
+    let mut cursor = source;
+
+    while let Some(marker) = cursor.find("pub enum ") {
+        let after = &cursor[marker + "pub enum ".len()..];
+        let enum_name = take_ident(after);
+
+        let after_name = &after[enum_name.len()..];
+        let (prefix, body) = match take_braced_block(after_name) {
+            Some(found) => found,
+            None => { cursor = after_name; continue; }
+        };
+
+        // Each variant line looks like `Name = 123,` (possibly with a doc
+        // comment or attribute above it); grabbing the identifier before the
+        // `=` is enough, no matter how the rest of the line is formatted.
+        let variants = body[1..body.len() - 1]
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with("//") && !line.starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .filter_map(|entry| entry.split('=').next())
+            .map(str::trim)
+            .filter(|ident| !ident.is_empty() && ident.chars().all(is_ident_char))
+            .map(str::to_string)
+            .collect();
+
+        variants_by_enum.insert(enum_name.to_string(), variants);
+        cursor = &after_name[prefix.len() + body.len()..];
+    }
+
+    variants_by_enum
+}
+
+/// Rewrites every `<enum_name>::<ident>` and `Self::<ident>` reference found
+/// in `body` whose `<ident>` is not a known variant of `enum_name`, stripping
+/// the `enum_name` prefix from `<ident>` to recover the real variant.
+fn fixup_variant_refs(body: &str, enum_name: &str, variants_by_enum: &HashMap<String, Vec<String>>) -> String {
+    let variants = variants_by_enum.get(enum_name);
+    let self_marker = format!("{}::", enum_name);
+
+    let mut output = String::with_capacity(body.len());
+    let mut cursor = body;
+
+    loop {
+        let next = ["Self::", self_marker.as_str()].iter()
+            .filter_map(|marker| cursor.find(marker).map(|pos| (pos, marker.len())))
+            .min_by_key(|&(pos, _)| pos);
+
+        let (pos, marker_len) = match next {
+            Some(found) => found,
+            None => break,
+        };
+
+        output.push_str(&cursor[..pos + marker_len]);
+        let after_marker = &cursor[pos + marker_len..];
+
+        let variant = take_ident(after_marker);
+        let known = variants
+            .map(|variants| variants.iter().any(|known| known == variant))
+            .unwrap_or(true);
+
+        if known {
+            output.push_str(variant);
+        } else {
+            output.push_str(variant.strip_prefix(enum_name).unwrap_or(variant));
+        }
+
+        cursor = &after_marker[variant.len()..];
+    }
+
+    output.push_str(cursor);
+    output
+}
+
+/// Returns the leading identifier (alphanumeric or `_`) of `s`.
+fn take_ident(s: &str) -> &str {
+    let len = s.find(|c: char| !is_ident_char(c)).unwrap_or(s.len());
+    &s[..len]
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Finds the first `{`...`}` block in `s` (tracking nested braces so it
+/// works regardless of how the block is spread across lines), returning the
+/// text before the opening brace and the block itself (braces included).
+fn take_braced_block(s: &str) -> Option<(&str, &str)> {
+    let start = s.find('{')?;
+
+    let mut depth = 0usize;
+    for (i, c) in s[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&s[..start], &s[start..start + i + 1]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
 mod file {
     use std::fs::File;
     use std::io::Result;